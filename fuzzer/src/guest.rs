@@ -1,95 +1,89 @@
 /// Driver for kernel to be tested
+pub mod pool;
+pub mod qmp;
+
 use crate::utils::cli::{App, Arg, OptVal};
 use crate::Config;
 use bytes::BytesMut;
 use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::sched::{sched_setaffinity, CpuSet};
+use nix::unistd::Pid;
 use os_pipe::{pipe, PipeReader, PipeWriter};
+use qmp::{Qmp, VirtualMachineState};
+use ssh2::Session;
 use std::collections::HashMap;
-use std::io::{ErrorKind, Read};
+use std::hash::{Hash, Hasher};
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
 use std::os::unix::io::AsRawFd;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration as StdDuration;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpListener;
 use tokio::process::Child;
+use tokio::task;
 use tokio::time::{delay_for, timeout, Duration};
 
+/// Base `-append` tokens for `linux/amd64`, kept separate from `ARCHES` so
+/// `build_qemu_cli` can merge in per-instance tokens (e.g. the boot
+/// handshake port) before joining them into one `-append` value.
+const LINUX_AMD64_APPEND: &[&str] = &[
+    "earlyprintk=serial",
+    "oops=panic",
+    "nmi_watchdog=panic",
+    "panic_on_warn=1",
+    "panic=1",
+    "ftrace_dump_on_oops=orig_cpu",
+    "rodata=n",
+    "vsyscall=native",
+    "net.ifnames=0",
+    "biosdevname=0",
+    "root=/dev/sda",
+    "console=ttyS0",
+    "kvm-intel.nested=1",
+    "kvm-intel.unrestricted_guest=1",
+    "kvm-intel.vmm_exclusive=1",
+    "kvm-intel.fasteoi=1",
+    "kvm-intel.ept=1",
+    "kvm-intel.flexpriority=1",
+    "kvm-intel.vpid=1",
+    "kvm-intel.emulate_invalid_guest_state=1",
+    "kvm-intel.eptad=1",
+    "kvm-intel.enable_shadow_vmcs=1",
+    "kvm-intel.pml=1",
+    "kvm-intel.enable_apicv=1",
+];
+
+/// Marker the guest writes once it has connected to the boot handshake
+/// listener, see `LinuxQemu::wait_boot_handshake`.
+const BOOT_HANDSHAKE_MARKER: &str = "booted";
+
+/// Per `os/arch` defaults, so adding a new target is a new entry in
+/// `ARCHES` rather than new code in `build_qemu_cli`.
+struct ArchProfile {
+    /// Default qemu binary, overridable via `QemuConf::qemu_bin`.
+    bin: &'static str,
+    /// Kernel cmdline tokens this target requires to boot and report
+    /// crashes deterministically; merged with `QemuConf::cmdline`.
+    append: &'static [&'static str],
+    cpu: &'static str,
+    net_model: &'static str,
+}
+
 lazy_static! {
-    static ref QEMUS: HashMap<String, App> = {
-        let mut qemus = HashMap::new();
-        let linux_amd64_append_vals = vec![
-            "earlyprintk=serial",
-            "oops=panic",
-            "nmi_watchdog=panic",
-            "panic_on_warn=1",
-            "panic=1",
-            "ftrace_dump_on_oops=orig_cpu",
-            "rodata=n",
-            "vsyscall=native",
-            "net.ifnames=0",
-            "biosdevname=0",
-            "root=/dev/sda",
-            "console=ttyS0",
-            "kvm-intel.nested=1",
-            "kvm-intel.unrestricted_guest=1",
-            "kvm-intel.vmm_exclusive=1",
-            "kvm-intel.fasteoi=1",
-            "kvm-intel.ept=1",
-            "kvm-intel.flexpriority=1",
-            "kvm-intel.vpid=1",
-            "kvm-intel.emulate_invalid_guest_state=1",
-            "kvm-intel.eptad=1",
-            "kvm-intel.enable_shadow_vmcs=1",
-            "kvm-intel.pml=1",
-            "kvm-intel.enable_apicv=1",
-        ];
-        let linux_amd64 = App::new("qemu-system-x86_64")
-            .arg(Arg::new_flag("-enable-kvm"))
-            .arg(Arg::new_flag("-no-reboot"))
-            .arg(Arg::new_opt("-display", OptVal::normal("none")))
-            .arg(Arg::new_opt("-serial", OptVal::normal("stdio")))
-            .arg(Arg::new_flag("-snapshot"))
-            .arg(Arg::new_opt(
-                "-cpu",
-                OptVal::multiple(vec!["host", "migratable=off"], Some(',')),
-            ))
-            .arg(Arg::new_opt(
-                "-net",
-                OptVal::multiple(vec!["nic", "model=e1000"], Some(',')),
-            ))
-            .arg(Arg::new_opt(
-                "-append",
-                OptVal::multiple(linux_amd64_append_vals, Some(' ')),
-            ));
-        qemus.insert("linux/amd64".to_string(), linux_amd64);
-
-        qemus
-    };
-    pub static ref SSH: App = {
-        App::new("ssh")
-            .arg(Arg::new_opt("-F", OptVal::normal("/dev/null")))
-            .arg(Arg::new_opt(
-                "-o",
-                OptVal::normal("UserKnownHostsFile=/dev/null"),
-            ))
-            .arg(Arg::new_opt("-o", OptVal::normal("BatchMode=yes")))
-            .arg(Arg::new_opt("-o", OptVal::normal("IdentitiesOnly=yes")))
-            .arg(Arg::new_opt(
-                "-o",
-                OptVal::normal("StrictHostKeyChecking=no"),
-            ))
-            .arg(Arg::new_opt("-o", OptVal::normal("ConnectTimeout=3s")))
-    };
-    pub static ref SCP: App = {
-        App::new("scp")
-            .arg(Arg::new_opt("-F", OptVal::normal("/dev/null")))
-            .arg(Arg::new_opt(
-                "-o",
-                OptVal::normal("UserKnownHostsFile=/dev/null"),
-            ))
-            .arg(Arg::new_opt("-o", OptVal::normal("BatchMode=yes")))
-            .arg(Arg::new_opt("-o", OptVal::normal("IdentitiesOnly=yes")))
-            .arg(Arg::new_opt(
-                "-o",
-                OptVal::normal("StrictHostKeyChecking=no"),
-            ))
+    static ref ARCHES: HashMap<&'static str, ArchProfile> = {
+        let mut arches = HashMap::new();
+        arches.insert(
+            "linux/amd64",
+            ArchProfile {
+                bin: "qemu-system-x86_64",
+                append: LINUX_AMD64_APPEND,
+                cpu: "host,migratable=off",
+                net_model: "nic,model=e1000",
+            },
+        );
+        arches
     };
 }
 
@@ -110,6 +104,22 @@ pub struct QemuConf {
     pub image: String,
     pub kernel: String,
     pub wait_boot_time: Option<u8>,
+    /// Number of parallel qemu instances to run, see `guest::pool::Pool`.
+    pub count: Option<u32>,
+    /// Override the target's default qemu binary.
+    pub qemu_bin: Option<String>,
+    /// Extra qemu flags appended verbatim, e.g. `["-usb"]`.
+    pub qemu_args: Option<Vec<String>>,
+    /// Initrd image, passed via `-initrd`.
+    pub initrd: Option<String>,
+    /// Extra kernel cmdline tokens, merged with the target's required ones.
+    pub cmdline: Option<Vec<String>>,
+    /// Device used to attach `image`, e.g. `-hda`, `-drive`, `virtio`.
+    /// Defaults to `-hda`.
+    pub image_device: Option<String>,
+    /// Host CPUs to pin this instance's qemu process to. With `Pool`,
+    /// each instance is handed a disjoint slice of this set.
+    pub cpus: Option<Vec<usize>>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -117,13 +127,22 @@ pub struct SSHConf {
     pub key_path: String,
 }
 
+/// Result of running an `App` on the guest over the persistent ssh session.
+#[derive(Debug)]
+pub struct CmdOutput {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub status: i32,
+}
+
 pub enum Guest {
     LinuxQemu(LinuxQemu),
 }
 
 impl Guest {
     pub fn new(cfg: &Config) -> Self {
-        // only support linux/amd64 on qemu now.
+        // qemu is the only supported platform; target os/arch is resolved
+        // against `ARCHES` inside `LinuxQemu::new`.
         Guest::LinuxQemu(LinuxQemu::new(cfg))
     }
 }
@@ -135,15 +154,19 @@ impl Guest {
         }
     }
 
-    pub async fn is_alive(&self) -> bool {
+    pub async fn is_alive(&mut self) -> bool {
         match self {
-            Guest::LinuxQemu(ref guest) => guest.is_alive().await,
+            Guest::LinuxQemu(ref mut guest) => guest.is_alive().await,
         }
     }
 
-    pub async fn run_cmd(&self, app: &App) -> Child {
+    /// Run `app` on the guest. `Err` means the ssh channel broke, almost
+    /// always because the kernel under test crashed mid-command; the
+    /// caller (`PoolGuest`) is expected to reboot the instance rather than
+    /// treat it as a process-fatal error.
+    pub async fn run_cmd(&mut self, app: &App) -> Result<CmdOutput, String> {
         match self {
-            Guest::LinuxQemu(ref guest) => guest.run_cmd(app).await,
+            Guest::LinuxQemu(ref mut guest) => guest.run_cmd(app).await,
         }
     }
 
@@ -152,6 +175,43 @@ impl Guest {
             Guest::LinuxQemu(ref mut guest) => guest.try_collect_crash().await,
         }
     }
+
+    /// Reset the guest back to its post-boot snapshot, much faster than a
+    /// full reboot via `boot`. Returns whether the reset succeeded.
+    pub async fn reset(&mut self) -> bool {
+        match self {
+            Guest::LinuxQemu(ref mut guest) => guest.reset().await,
+        }
+    }
+
+    /// Pause the VM, e.g. to inspect it during crash triage.
+    pub async fn pause(&mut self) {
+        match self {
+            Guest::LinuxQemu(ref mut guest) => guest.pause().await,
+        }
+    }
+
+    /// Resume a paused VM.
+    pub async fn resume(&mut self) {
+        match self {
+            Guest::LinuxQemu(ref mut guest) => guest.resume().await,
+        }
+    }
+
+    pub async fn status(&mut self) -> VirtualMachineState {
+        match self {
+            Guest::LinuxQemu(ref mut guest) => guest.status().await,
+        }
+    }
+
+    /// Pin this instance's qemu process to `cpus` on its next boot, or
+    /// clear pinning entirely when `cpus` is `None`. See
+    /// `guest::pool::Pool`, which hands out a disjoint slice per instance.
+    pub fn set_pinned_cpus(&mut self, cpus: Option<Vec<usize>>) {
+        match self {
+            Guest::LinuxQemu(ref mut guest) => guest.set_pinned_cpus(cpus),
+        }
+    }
 }
 
 pub const LINUX_QEMU_HOST_IP_ADDR: &str = "localhost";
@@ -169,15 +229,27 @@ pub struct LinuxQemu {
     port: u16,
     key: String,
     user: String,
+
+    qmp_sock: PathBuf,
+    qmp: Option<Qmp>,
+
+    session: Option<Arc<StdMutex<Session>>>,
+    uploaded_hash: Option<u64>,
+
+    boot_listener: TcpListener,
+
+    pin_cpus: Option<Vec<usize>>,
 }
 
 impl LinuxQemu {
     pub fn new(cfg: &Config) -> Self {
         assert_eq!(cfg.guest.platform.trim(), "qemu");
-        assert_eq!(cfg.guest.os, "linux");
-        assert_eq!(cfg.guest.arch, "amd64");
+        // os/arch support is driven entirely by `ARCHES`/`build_qemu_cli`,
+        // which already exits with a clear config error for anything not
+        // in the table, so adding e.g. `linux/arm64` is a new profile entry
+        // rather than a new hardcoded target here.
 
-        let (qemu, port) = build_qemu_cli(&cfg);
+        let (qemu, port, qmp_sock, boot_listener) = build_qemu_cli(&cfg);
         let ssh_conf = cfg
             .ssh
             .as_ref()
@@ -193,6 +265,16 @@ impl LinuxQemu {
             port,
             key: ssh_conf.key_path.clone(),
             user: LINUX_QEMU_HOST_USER.to_string(),
+
+            qmp_sock,
+            qmp: None,
+
+            session: None,
+            uploaded_hash: None,
+
+            boot_listener,
+
+            pin_cpus: cfg.qemu.as_ref().and_then(|q| q.cpus.clone()),
         }
     }
 }
@@ -200,12 +282,24 @@ impl LinuxQemu {
 impl LinuxQemu {
     async fn boot(&mut self) {
         const MAX_RETRY: u8 = 5;
+        const MAX_QMP_RETRY: u8 = 10;
 
         if let Some(ref mut h) = self.handle {
             h.kill()
                 .unwrap_or_else(|e| exits!(exitcode::OSERR, "Fail to kill:{}", e));
             self.rp = None;
         }
+        // Clear unconditionally, not just when `handle` was still `Some`:
+        // `try_collect_crash` already nulls out `handle`/`rp` as soon as it
+        // detects a crash, so a guest rebooted after that path would
+        // otherwise keep its stale, dead ssh session forever — `is_alive`
+        // never detects a broken-but-`Some` session on its own, so every
+        // future checkout would see it as not-alive and reboot it again
+        // without ever clearing the bad session.
+        self.qmp = None;
+        self.session = None;
+        self.uploaded_hash = None;
+        let _ = std::fs::remove_file(&self.qmp_sock);
 
         let (mut handle, mut rp) = {
             let mut cmd = self.vm.clone().into_cmd();
@@ -224,62 +318,243 @@ impl LinuxQemu {
             (handle, rp)
         };
 
-        let mut retry = 1;
-        loop {
-            delay_for(Duration::new(self.wait_boot_time as u64, 0)).await;
+        if let Some(cpus) = &self.pin_cpus {
+            pin_process(&handle, cpus);
+        }
 
-            if self.is_alive().await {
-                break;
+        // qemu creates the qmp socket as soon as it starts, well before
+        // the guest kernel has booted, so this can be connected early and
+        // used to confirm boot and take the reset snapshot below.
+        let mut qmp_retry = 0;
+        let qmp = loop {
+            if self.qmp_sock.exists() {
+                break Qmp::connect(&self.qmp_sock).await;
+            }
+            if qmp_retry == MAX_QMP_RETRY {
+                exits!(
+                    exitcode::OSERR,
+                    "Fail to find qmp socket:{:?}",
+                    self.qmp_sock
+                );
             }
+            qmp_retry += 1;
+            delay_for(Duration::from_millis(200)).await;
+        };
+        self.qmp = Some(qmp);
+
+        // Primary readiness signal: wait for the guest to connect back and
+        // write the handshake marker, deterministic and much faster than
+        // blindly sleeping `wait_boot_time` before the first SSH probe.
+        let handshake_budget = Duration::new(self.wait_boot_time as u64 * MAX_RETRY as u64, 0);
+        let booted = match timeout(handshake_budget, self.wait_boot_handshake()).await {
+            Ok(ok) => ok,
+            Err(_) => false,
+        };
 
-            if retry == MAX_RETRY {
-                handle
-                    .kill()
-                    .unwrap_or_else(|e| exits!(exitcode::OSERR, "Fail to kill:{}", e));
-                let mut buf = String::new();
-                rp.read_to_string(&mut buf).unwrap_or_else(|e| {
-                    exits!(exitcode::OSERR, "Fail to read to end of pipe:{}", e)
-                });
-                eprintln!("{}", buf);
-                eprintln!("===============================================");
-                exits!(exitcode::DATAERR, "Fail to boot :\n{:?}", self.vm);
+        if !booted {
+            // Fall back to polling the SSH probe, as before the handshake
+            // existed; this keeps boot working against guests/images that
+            // don't yet connect back on the handshake port.
+            let mut retry = 1;
+            loop {
+                delay_for(Duration::new(self.wait_boot_time as u64, 0)).await;
+
+                if self.is_alive().await {
+                    break;
+                }
+
+                if retry == MAX_RETRY {
+                    handle
+                        .kill()
+                        .unwrap_or_else(|e| exits!(exitcode::OSERR, "Fail to kill:{}", e));
+                    let mut buf = String::new();
+                    rp.read_to_string(&mut buf).unwrap_or_else(|e| {
+                        exits!(exitcode::OSERR, "Fail to read to end of pipe:{}", e)
+                    });
+                    eprintln!("{}", buf);
+                    eprintln!("===============================================");
+                    exits!(exitcode::DATAERR, "Fail to boot :\n{:?}", self.vm);
+                }
+                retry += 1;
             }
-            retry += 1;
         }
         // clear useless data in pipe
         read_until_block(&mut rp);
         self.handle = Some(handle);
         self.rp = Some(rp);
+
+        // Guest is confirmed alive: snapshot it so later executions can
+        // reset to this point instead of paying for a full reboot. Fatal if
+        // it fails (e.g. the image doesn't support internal snapshots),
+        // since every instance in the pool relies on this snapshot existing.
+        self.qmp.as_mut().unwrap().save_snapshot().await.unwrap_or_else(|e| {
+            exits!(
+                exitcode::SOFTWARE,
+                "Fail to save boot snapshot, does {:?} support internal snapshots?:{}",
+                self.vm,
+                e
+            )
+        });
     }
 
-    async fn is_alive(&self) -> bool {
-        let mut pwd = ssh_app(
-            &self.key,
-            &self.user,
-            &self.addr,
-            self.port,
-            App::new("pwd"),
-        )
-        .into_cmd();
-        pwd.stdin(std::process::Stdio::piped())
-            .stdout(std::process::Stdio::piped())
-            .stderr(std::process::Stdio::piped());
-        match timeout(Duration::new(10, 0), pwd.status()).await {
+    /// Block until the guest connects to `boot_listener` and writes the
+    /// handshake marker, confirming boot without polling SSH.
+    async fn wait_boot_handshake(&mut self) -> bool {
+        let (mut stream, _) = match self.boot_listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(_) => return false,
+        };
+        let mut buf = [0u8; BOOT_HANDSHAKE_MARKER.len()];
+        match stream.read_exact(&mut buf).await {
+            Ok(()) => buf == BOOT_HANDSHAKE_MARKER.as_bytes(),
             Err(_) => false,
-            Ok(status) => match status {
-                Ok(status) => status.success(),
-                Err(e) => exits!(exitcode::OSERR, "Fail to spawn:{}", e),
-            },
         }
     }
 
-    async fn run_cmd(&self, app: &App) -> Child {
-        assert!(self.handle.is_some());
+    /// Reset the guest back to the post-boot snapshot instead of
+    /// rebooting it, for use between test executions. Returns whether the
+    /// reset succeeded; on success the host-side ssh session is dropped,
+    /// since restoring a snapshot rewinds the guest's TCP state and leaves
+    /// any previously-established connection stale — the next `is_alive`/
+    /// `run_cmd` call reconnects lazily.
+    async fn reset(&mut self) -> bool {
+        match self.qmp.as_mut().unwrap().load_snapshot().await {
+            Ok(()) => {
+                self.session = None;
+                true
+            }
+            Err(_) => false,
+        }
+    }
 
-        let mut app = app.clone();
-        let bin = PathBuf::from(app.bin);
-        scp(&self.key, &self.user, &self.addr, self.port, &bin).await;
+    /// Pause the VM, e.g. to inspect it during crash triage.
+    async fn pause(&mut self) {
+        self.qmp.as_mut().unwrap().stop().await;
+    }
+
+    /// Resume a paused VM.
+    async fn resume(&mut self) {
+        self.qmp.as_mut().unwrap().cont().await;
+    }
+
+    /// Pin this instance's qemu process to `cpus` on its next boot, or
+    /// clear pinning entirely when `cpus` is `None`, overriding any set
+    /// configured via `QemuConf::cpus`. Used by `Pool` to keep instances
+    /// from contending for the same host cores.
+    fn set_pinned_cpus(&mut self, cpus: Option<Vec<usize>>) {
+        self.pin_cpus = cpus;
+    }
+
+    async fn status(&mut self) -> VirtualMachineState {
+        self.qmp.as_mut().unwrap().query_status().await
+    }
+
+    /// Liveness probe. While no session is established yet (e.g. during
+    /// the boot retry loop) this attempts the initial connect+auth;
+    /// afterwards it reuses the persistent session to run `pwd`.
+    async fn is_alive(&mut self) -> bool {
+        if self.session.is_none() {
+            return self.try_connect_ssh().await;
+        }
+
+        let session = self.session.as_ref().unwrap().clone();
+        task::spawn_blocking(move || {
+            let sess = session.lock().unwrap();
+            sess.channel_session()
+                .and_then(|mut ch| ch.exec("pwd").and_then(|_| ch.wait_close()))
+                .is_ok()
+        })
+        .await
+        .unwrap_or(false)
+    }
+
+    /// Open the persistent ssh session used for the rest of this guest's
+    /// lifetime, authenticating with the configured key.
+    async fn try_connect_ssh(&mut self) -> bool {
+        let addr = self.addr.clone();
+        let port = self.port;
+        let key = self.key.clone();
+        let user = self.user.clone();
+
+        let session = task::spawn_blocking(move || -> Option<Session> {
+            let tcp = TcpStream::connect((addr.as_str(), port)).ok()?;
+            tcp.set_read_timeout(Some(StdDuration::new(3, 0))).ok()?;
+            let mut sess = Session::new().ok()?;
+            sess.set_tcp_stream(tcp);
+            sess.handshake().ok()?;
+            sess.userauth_pubkey_file(&user, None, Path::new(&key), None)
+                .ok()?;
+            Some(sess)
+        })
+        .await
+        .unwrap_or_else(|e| exits!(exitcode::OSERR, "Ssh: connect task panicked:{}", e));
+
+        match session {
+            Some(sess) => {
+                self.session = Some(Arc::new(StdMutex::new(sess)));
+                true
+            }
+            None => false,
+        }
+    }
 
+    /// Upload `path` over SFTP to the guest's home directory, skipping the
+    /// transfer if its content hash matches the last upload.
+    async fn upload_if_changed(&mut self, path: &PathBuf) {
+        let content = tokio::fs::read(path)
+            .await
+            .unwrap_or_else(|e| exits!(exitcode::OSERR, "Fail to read {:?}:{}", path, e));
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        content.hash(&mut hasher);
+        let hash = hasher.finish();
+        if self.uploaded_hash == Some(hash) {
+            return;
+        }
+
+        let name = path
+            .file_name()
+            .unwrap_or_else(|| exits!(exitcode::DATAERR, "Bad app:{:?}", path))
+            .to_str()
+            .unwrap()
+            .to_string();
+        let session = self.session.as_ref().unwrap().clone();
+        task::spawn_blocking(move || {
+            let sess = session.lock().unwrap();
+            let sftp = sess
+                .sftp()
+                .unwrap_or_else(|e| exits!(exitcode::OSERR, "Sftp: fail to open:{}", e));
+            let remote = PathBuf::from(format!("/root/{}", name));
+            let mut remote_file = sftp
+                .create(&remote)
+                .unwrap_or_else(|e| exits!(exitcode::OSERR, "Sftp: fail to create {:?}:{}", remote, e));
+            remote_file
+                .write_all(&content)
+                .unwrap_or_else(|e| exits!(exitcode::OSERR, "Sftp: fail to write {:?}:{}", remote, e));
+        })
+        .await
+        .unwrap_or_else(|e| exits!(exitcode::OSERR, "Sftp: upload task panicked:{}", e));
+
+        self.uploaded_hash = Some(hash);
+    }
+
+    /// Run `app` over the persistent ssh session. A broken channel (`Err`)
+    /// almost always means the kernel under test panicked mid-command and
+    /// took qemu's side of the connection down with it — that's the
+    /// primary way fuzzing finds bugs under `-no-reboot`/`panic=1`, not an
+    /// infra failure, so it's reported back instead of `exits!`ing the
+    /// whole multi-VM pool over one instance's crash.
+    async fn run_cmd(&mut self, app: &App) -> Result<CmdOutput, String> {
+        // Lazily connect, same as `is_alive`: a guest that booted via the
+        // TCP handshake (skipping the SSH fallback loop) or that was just
+        // `reset` (which drops the now-stale session) won't have one yet.
+        if self.session.is_none() && !self.try_connect_ssh().await {
+            exits!(exitcode::OSERR, "Ssh: fail to connect to {}:{}", self.addr, self.port);
+        }
+
+        let bin = PathBuf::from(app.bin.clone());
+        self.upload_if_changed(&bin).await;
+
+        let mut app = app.clone();
         app.bin = format!(
             "~/{}",
             bin.file_name()
@@ -287,18 +562,62 @@ impl LinuxQemu {
                 .to_str()
                 .unwrap()
         );
+        let mut cmd_line = vec![app.bin.clone()];
+        cmd_line.extend(app.iter_arg());
+        let cmd_line = cmd_line.join(" ");
+
+        let session = self.session.as_ref().unwrap().clone();
+        let result = task::spawn_blocking(move || -> Result<CmdOutput, String> {
+            let sess = session.lock().unwrap();
+            let mut channel = sess
+                .channel_session()
+                .map_err(|e| format!("fail to open channel:{}", e))?;
+            channel
+                .exec(&cmd_line)
+                .map_err(|e| format!("fail to exec `{}`:{}", cmd_line, e))?;
+
+            let mut stdout = Vec::new();
+            channel
+                .read_to_end(&mut stdout)
+                .map_err(|e| format!("fail to read stdout:{}", e))?;
+            let mut stderr = Vec::new();
+            channel
+                .stderr()
+                .read_to_end(&mut stderr)
+                .map_err(|e| format!("fail to read stderr:{}", e))?;
+            channel
+                .wait_close()
+                .map_err(|e| format!("fail to close channel:{}", e))?;
+            let status = channel
+                .exit_status()
+                .map_err(|e| format!("fail to get exit status:{}", e))?;
+
+            Ok(CmdOutput {
+                stdout,
+                stderr,
+                status,
+            })
+        })
+        .await
+        .unwrap_or_else(|e| exits!(exitcode::OSERR, "Ssh: exec task panicked:{}", e));
 
-        let mut app = ssh_app(&self.key, &self.user, &self.addr, self.port, app).into_cmd();
-        app.stdin(std::process::Stdio::piped())
-            .stdout(std::process::Stdio::piped())
-            .stderr(std::process::Stdio::piped())
-            .kill_on_drop(true)
-            .spawn()
-            .unwrap_or_else(|e| exits!(exitcode::OSERR, "Fail to spawn:{}", e))
+        if result.is_err() {
+            // The session is almost certainly dead along with the channel;
+            // drop it so the next call reconnects instead of reusing a
+            // socket to a qemu process that may no longer exist.
+            self.session = None;
+        }
+        result
     }
 
+    /// Non-blocking check for a pending crash. Returns `None` (rather than
+    /// asserting) once the crash has already been collected and the guest
+    /// is just waiting to be rebooted, since `collect_crashes` polls every
+    /// slot on each tick regardless of whether it was already handled.
     async fn try_collect_crash(&mut self) -> Option<String> {
-        assert!(self.rp.is_some());
+        if self.rp.is_none() {
+            return None;
+        }
         match timeout(Duration::new(2, 0), self.handle.as_mut().unwrap()).await {
             Err(_e) => None,
             Ok(_) => {
@@ -316,26 +635,68 @@ impl LinuxQemu {
     }
 }
 
-fn build_qemu_cli(cfg: &Config) -> (App, u16) {
-    let target = format!("{}/{}", cfg.guest.os, cfg.guest.arch);
+/// Build the arg(s) attaching `image`, from `QemuConf::image_device`
+/// (default `"-hda"`). `-drive`/`"virtio"` need `key=value` syntax rather
+/// than a bare path, so they're special-cased; anything else (`-hda`,
+/// `-cdrom`, ...) is passed through as a plain flag+value.
+fn image_device_arg(image_device: &str, image: &str) -> Arg {
+    match image_device.trim_start_matches('-') {
+        "drive" => Arg::new_opt("-drive", OptVal::normal(&format!("file={}", image))),
+        "virtio" => Arg::new_opt(
+            "-drive",
+            OptVal::normal(&format!("file={},if=virtio", image)),
+        ),
+        _ => Arg::new_opt(image_device, OptVal::Normal(image.to_string())),
+    }
+}
 
-    let default_qemu = QEMUS
-        .get(&target)
-        .unwrap_or_else(|| exits!(exitcode::CONFIG, "Unsupported target:{}", &target))
-        .clone();
+fn build_qemu_cli(cfg: &Config) -> (App, u16, PathBuf, TcpListener) {
+    let target = format!("{}/{}", cfg.guest.os, cfg.guest.arch);
+    let profile = ARCHES
+        .get(target.as_str())
+        .unwrap_or_else(|| exits!(exitcode::CONFIG, "Unsupported target:{}", &target));
 
     let port = port_check::free_local_port()
         .unwrap_or_else(|| exits!(exitcode::TEMPFAIL, "No Free port to forword"));
+    let qmp_sock = std::env::temp_dir().join(format!("healer-qmp-{}.sock", port));
+
+    let boot_port = port_check::free_local_port()
+        .unwrap_or_else(|| exits!(exitcode::TEMPFAIL, "No Free port for boot handshake"));
+    let boot_listener = std::net::TcpListener::bind(("0.0.0.0", boot_port))
+        .unwrap_or_else(|e| exits!(exitcode::OSERR, "Fail to bind boot handshake port:{}", e));
+    boot_listener
+        .set_nonblocking(true)
+        .unwrap_or_else(|e| exits!(exitcode::OSERR, "Fail to set boot listener nonblocking:{}", e));
+    let boot_listener = TcpListener::from_std(boot_listener)
+        .unwrap_or_else(|e| exits!(exitcode::OSERR, "Fail to register boot listener:{}", e));
+
     let cfg = &cfg
         .qemu
         .as_ref()
         .unwrap_or_else(|| exits!(exitcode::SOFTWARE, "Require qemu segment in config toml"));
-    let qemu = default_qemu
+
+    let bin = cfg.qemu_bin.clone().unwrap_or_else(|| profile.bin.to_string());
+    let image_device = cfg.image_device.clone().unwrap_or_else(|| "-hda".to_string());
+
+    let mut append_vals: Vec<String> = profile.append.iter().map(|s| s.to_string()).collect();
+    if let Some(extra) = &cfg.cmdline {
+        append_vals.extend(extra.iter().cloned());
+    }
+    append_vals.push(format!("healer_boot_port={}", boot_port));
+
+    let mut qemu = App::new(bin)
+        .arg(Arg::new_flag("-enable-kvm"))
+        .arg(Arg::new_flag("-no-reboot"))
+        .arg(Arg::new_opt("-display", OptVal::normal("none")))
+        .arg(Arg::new_opt("-serial", OptVal::normal("stdio")))
+        .arg(Arg::new_flag("-snapshot"))
+        .arg(Arg::new_opt("-cpu", OptVal::normal(profile.cpu)))
         .arg(Arg::new_opt("-m", OptVal::Normal(cfg.mem_size.to_string())))
         .arg(Arg::new_opt(
             "-smp",
             OptVal::Normal(cfg.cpu_num.to_string()),
         ))
+        .arg(Arg::new_opt("-net", OptVal::normal(profile.net_model)))
         .arg(Arg::new_opt(
             "-net",
             OptVal::Multiple {
@@ -347,41 +708,48 @@ fn build_qemu_cli(cfg: &Config) -> (App, u16) {
                 sp: Some(','),
             },
         ))
-        .arg(Arg::new_opt("-hda", OptVal::Normal(cfg.image.clone())))
-        .arg(Arg::new_opt("-kernel", OptVal::Normal(cfg.kernel.clone())));
-    (qemu, port)
-}
+        .arg(image_device_arg(&image_device, &cfg.image))
+        .arg(Arg::new_opt("-kernel", OptVal::Normal(cfg.kernel.clone())))
+        .arg(Arg::new_opt(
+            "-append",
+            OptVal::Multiple {
+                vals: append_vals,
+                sp: Some(' '),
+            },
+        ))
+        .arg(Arg::new_opt(
+            "-qmp",
+            OptVal::normal(&format!("unix:{},server,nowait", qmp_sock.display())),
+        ));
 
-fn ssh_app(key: &str, user: &str, addr: &str, port: u16, app: App) -> App {
-    let mut ssh = SSH
-        .clone()
-        .arg(Arg::new_opt("-p", OptVal::normal(&port.to_string())))
-        .arg(Arg::new_opt("-i", OptVal::normal(key)))
-        .arg(Arg::Flag(format!("{}@{}", user, addr)))
-        .arg(Arg::new_flag(&app.bin));
-    for app_arg in app.iter_arg() {
-        ssh = ssh.arg(Arg::Flag(app_arg));
-    }
-    ssh
-}
+    if let Some(initrd) = &cfg.initrd {
+        qemu = qemu.arg(Arg::new_opt("-initrd", OptVal::Normal(initrd.clone())));
+    }
+    if let Some(extra_args) = &cfg.qemu_args {
+        for raw in extra_args {
+            qemu = qemu.arg(Arg::new_flag(raw));
+        }
+    }
 
-async fn scp(key: &str, user: &str, addr: &str, port: u16, path: &PathBuf) {
-    let scp = SCP
-        .clone()
-        .arg(Arg::new_opt("-P", OptVal::normal(&port.to_string())))
-        .arg(Arg::new_opt("-i", OptVal::normal(key)))
-        .arg(Arg::new_flag(path.as_path().to_str().unwrap()))
-        .arg(Arg::Flag(format!("{}@{}:~/", user, addr)));
-
-    let output = scp
-        .into_cmd()
-        .output()
-        .await
-        .unwrap_or_else(|e| panic!("Failed to spawn:{}", e));
+    (qemu, port, qmp_sock, boot_listener)
+}
 
-    if !output.status.success() {
-        panic!(String::from_utf8(output.stderr).unwrap())
+/// Restrict `child` to run only on `cpus`, so timing-sensitive crash
+/// reproduction isn't made noisy by the scheduler migrating it across all
+/// host cores, and so parallel instances in a `Pool` don't contend for
+/// the same cache.
+fn pin_process(child: &Child, cpus: &[usize]) {
+    let pid = child
+        .id()
+        .unwrap_or_else(|| exits!(exitcode::SOFTWARE, "Fail to get pid of spawned qemu"));
+
+    let mut set = CpuSet::new();
+    for &cpu in cpus {
+        set.set(cpu)
+            .unwrap_or_else(|e| exits!(exitcode::CONFIG, "Bad cpu id {}:{}", cpu, e));
     }
+    sched_setaffinity(Pid::from_raw(pid as i32), &set)
+        .unwrap_or_else(|e| exits!(exitcode::OSERR, "Fail to pin qemu to cpus {:?}:{}", cpus, e));
 }
 
 fn long_pipe() -> (PipeReader, PipeWriter) {
@@ -430,4 +798,31 @@ fn read_until_block(rp: &mut PipeReader) -> BytesMut {
     }
     result.truncate(count);
     result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::image_device_arg;
+
+    #[test]
+    fn hda_is_passed_through_as_a_bare_flag_and_value() {
+        let arg = format!("{:?}", image_device_arg("-hda", "/img.qcow2"));
+        assert!(arg.contains("-hda"));
+        assert!(arg.contains("/img.qcow2"));
+        assert!(!arg.contains("file="));
+    }
+
+    #[test]
+    fn drive_is_rewritten_to_key_value_syntax() {
+        let arg = format!("{:?}", image_device_arg("-drive", "/img.qcow2"));
+        assert!(arg.contains("-drive"));
+        assert!(arg.contains("file=/img.qcow2"));
+    }
+
+    #[test]
+    fn virtio_is_rewritten_to_a_drive_with_if_virtio() {
+        let arg = format!("{:?}", image_device_arg("virtio", "/img.qcow2"));
+        assert!(arg.contains("-drive"));
+        assert!(arg.contains("file=/img.qcow2,if=virtio"));
+    }
 }
\ No newline at end of file
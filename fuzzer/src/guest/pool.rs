@@ -0,0 +1,250 @@
+/// A pool of parallel qemu instances.
+///
+/// syzkaller's qemu config exposes a `count` field so the fuzzer can fan
+/// executions out across many VMs instead of being bottlenecked on one;
+/// `Pool` boots `count` `Guest`s up front and hands idle, alive ones out
+/// to callers, rebooting any that die in the background.
+use super::{CmdOutput, Guest};
+use crate::utils::cli::App;
+use crate::Config;
+use std::cell::Cell;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task;
+
+struct Slot {
+    guest: Mutex<Guest>,
+}
+
+/// Split `cfg.qemu.cpus` into `n` disjoint, roughly-equal slices so each
+/// pooled instance gets its own host cores instead of contending for all
+/// of them. Returns `n` `None`s when no CPU set is configured.
+fn disjoint_cpu_slices(cfg: &Config, n: usize) -> Vec<Option<Vec<usize>>> {
+    disjoint_cpu_slices_of(cfg.qemu.as_ref().and_then(|q| q.cpus.clone()), n)
+}
+
+/// Slice math behind [`disjoint_cpu_slices`], pulled out so it can be
+/// tested without building a `Config`.
+fn disjoint_cpu_slices_of(cpus: Option<Vec<usize>>, n: usize) -> Vec<Option<Vec<usize>>> {
+    let cpus = match cpus {
+        Some(cpus) if !cpus.is_empty() => cpus,
+        _ => return vec![None; n],
+    };
+
+    let per = (cpus.len() / n).max(1);
+    cpus.chunks(per)
+        .take(n)
+        .map(|slice| Some(slice.to_vec()))
+        .chain(std::iter::repeat(None))
+        .take(n)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::disjoint_cpu_slices_of;
+
+    #[test]
+    fn none_when_unconfigured() {
+        assert_eq!(disjoint_cpu_slices_of(None, 3), vec![None, None, None]);
+        assert_eq!(disjoint_cpu_slices_of(Some(vec![]), 3), vec![None, None, None]);
+    }
+
+    #[test]
+    fn splits_evenly_divisible_set() {
+        let got = disjoint_cpu_slices_of(Some(vec![0, 1, 2, 3, 4, 5]), 3);
+        assert_eq!(
+            got,
+            vec![Some(vec![0, 1]), Some(vec![2, 3]), Some(vec![4, 5])]
+        );
+    }
+
+    #[test]
+    fn remainder_cpus_are_left_unused() {
+        // 5 cpus / 2 instances -> per-slice size 2, so the 2 slices take
+        // [0,1] and [2,3], leaving cpu 4 unused rather than panicking or
+        // handing out an uneven 3rd slice.
+        let got = disjoint_cpu_slices_of(Some(vec![0, 1, 2, 3, 4]), 2);
+        assert_eq!(got, vec![Some(vec![0, 1]), Some(vec![2, 3])]);
+    }
+
+    #[test]
+    fn more_instances_than_cpus_pads_with_none() {
+        // Slices shrink to one cpu each, so only two instances get a cpu;
+        // the rest must be `None` (see the `set_pinned_cpus(slice)` comment
+        // in `Pool::new` about not leaving later instances on the default
+        // unsliced set).
+        let got = disjoint_cpu_slices_of(Some(vec![0, 1]), 4);
+        assert_eq!(got, vec![Some(vec![0]), Some(vec![1]), None, None]);
+    }
+}
+
+pub struct Pool {
+    slots: Vec<Arc<Slot>>,
+    idle: Mutex<mpsc::Receiver<usize>>,
+    back: mpsc::Sender<usize>,
+}
+
+impl Pool {
+    /// Boot `cfg.qemu.count` (default 1) guests concurrently.
+    pub async fn new(cfg: &Config) -> Self {
+        let n = cfg
+            .qemu
+            .as_ref()
+            .and_then(|q| q.count)
+            .unwrap_or(1)
+            .max(1) as usize;
+
+        let cpu_slices = disjoint_cpu_slices(cfg, n);
+
+        let mut boots = Vec::with_capacity(n);
+        for slice in cpu_slices.into_iter() {
+            let mut guest = Guest::new(cfg);
+            boots.push(task::spawn(async move {
+                // Always set pinning explicitly, even to `None`: otherwise
+                // an instance that ran out of cpu slices (more instances
+                // than configured cpus) would keep `LinuxQemu::new`'s
+                // default of the whole, unsliced `cpus` set and end up
+                // pinned to every core another instance already owns.
+                guest.set_pinned_cpus(slice);
+                guest.boot().await;
+                guest
+            }));
+        }
+
+        let mut slots = Vec::with_capacity(n);
+        for h in boots {
+            let guest = h
+                .await
+                .unwrap_or_else(|e| exits!(exitcode::OSERR, "Pool: boot task panicked:{}", e));
+            slots.push(Arc::new(Slot {
+                guest: Mutex::new(guest),
+            }));
+        }
+
+        let (back, idle) = mpsc::channel(n);
+        for i in 0..n {
+            back.clone().send(i).await.unwrap_or_else(|e| {
+                exits!(exitcode::OSERR, "Pool: fail to seed idle queue:{}", e)
+            });
+        }
+
+        Self {
+            slots,
+            idle: Mutex::new(idle),
+            back,
+        }
+    }
+
+    /// Number of guests managed by this pool.
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Block until an idle, alive guest is available. Dead guests are
+    /// rebooted in the background and are not handed out until they come
+    /// back up.
+    pub async fn checkout(&self) -> PoolGuest<'_> {
+        loop {
+            let idx = self
+                .idle
+                .lock()
+                .await
+                .recv()
+                .await
+                .unwrap_or_else(|| exits!(exitcode::SOFTWARE, "Pool: idle queue closed"));
+            let slot = self.slots[idx].clone();
+
+            let alive = slot.guest.lock().await.is_alive().await;
+            if alive {
+                return PoolGuest {
+                    back: self.back.clone(),
+                    idx,
+                    slot,
+                    requeue: Cell::new(true),
+                };
+            }
+            reboot_in_background(self.back.clone(), idx, slot);
+        }
+    }
+
+    /// Collect crashes from every instance that has one pending, waiting at
+    /// most 2 seconds on each guest that is still running. Each crashed instance is
+    /// rebooted in the background, same as a guest found dead on
+    /// `checkout`, instead of being left to crash-loop forever.
+    pub async fn collect_crashes(&self) -> Vec<String> {
+        let mut crashes = Vec::new();
+        for (idx, slot) in self.slots.iter().enumerate() {
+            if let Some(crash) = slot.guest.lock().await.try_collect_crash().await {
+                crashes.push(crash);
+                reboot_in_background(self.back.clone(), idx, slot.clone());
+            }
+        }
+        crashes
+    }
+}
+
+/// Reboot the guest at `idx` and return it to the idle queue once it's back
+/// up. Used both for guests found dead on checkout and for guests whose
+/// post-run reset failed.
+fn reboot_in_background(back: mpsc::Sender<usize>, idx: usize, slot: Arc<Slot>) {
+    task::spawn(async move {
+        slot.guest.lock().await.boot().await;
+        back.send(idx).await.unwrap_or_else(|e| {
+            exits!(exitcode::OSERR, "Pool: fail to return rebooted guest:{}", e)
+        });
+    });
+}
+
+/// A guest checked out of a [`Pool`], returned to the idle queue on drop.
+pub struct PoolGuest<'p> {
+    back: mpsc::Sender<usize>,
+    idx: usize,
+    slot: Arc<Slot>,
+    /// Cleared when a post-run reset fails and a background reboot has
+    /// already taken over returning this slot to the idle queue, so `Drop`
+    /// doesn't hand out a guest that isn't alive yet.
+    requeue: Cell<bool>,
+}
+
+impl<'p> PoolGuest<'p> {
+    /// Run `app` and restore the guest to its post-boot snapshot
+    /// afterwards, so the next checkout starts from clean state instead of
+    /// accumulating whatever the previous execution left behind. `None`
+    /// means the guest crashed (the expected way fuzzing finds bugs under
+    /// `-no-reboot`/`panic=1`); if only the post-run reset fails, the
+    /// output is still returned. Either way the guest is rebooted in the
+    /// background instead of being handed out broken, so one instance's
+    /// crash doesn't take the rest of the pool down.
+    pub async fn run_cmd(&self, app: &App) -> Option<CmdOutput> {
+        let mut guest = self.slot.guest.lock().await;
+        let out = match guest.run_cmd(app).await {
+            Ok(out) => out,
+            Err(_) => {
+                drop(guest);
+                self.requeue.set(false);
+                reboot_in_background(self.back.clone(), self.idx, self.slot.clone());
+                return None;
+            }
+        };
+        if !guest.reset().await {
+            drop(guest);
+            self.requeue.set(false);
+            reboot_in_background(self.back.clone(), self.idx, self.slot.clone());
+        }
+        Some(out)
+    }
+}
+
+impl<'p> Drop for PoolGuest<'p> {
+    fn drop(&mut self) {
+        if !self.requeue.get() {
+            return;
+        }
+        let mut back = self.back.clone();
+        let idx = self.idx;
+        task::spawn(async move {
+            back.send(idx).await.ok();
+        });
+    }
+}
@@ -0,0 +1,196 @@
+/// QMP (QEMU Machine Protocol) control channel.
+///
+/// Gives `LinuxQemu` a way to talk to a running instance without tearing
+/// it down: querying status, pausing/resuming for crash triage, and
+/// snapshotting for fast reset between executions instead of a full
+/// reboot.
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+use tokio::time::{timeout, Duration};
+
+const QMP_TIMEOUT: Duration = Duration::from_secs(10);
+const SNAPSHOT_TAG: &str = "healer_base";
+
+/// Status of a running VM, as reported by `query-status`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtualMachineState {
+    Stopped,
+    Paused,
+    Running,
+}
+
+pub struct Qmp {
+    sock: PathBuf,
+    io: BufReader<UnixStream>,
+}
+
+impl Qmp {
+    /// Connect to the socket qemu was launched with `-qmp
+    /// unix:<sock>,server,nowait` and complete the capabilities handshake.
+    pub async fn connect(sock: &Path) -> Self {
+        let stream = UnixStream::connect(sock)
+            .await
+            .unwrap_or_else(|e| exits!(exitcode::OSERR, "Qmp: fail to connect {:?}:{}", sock, e));
+        let mut qmp = Self {
+            sock: sock.to_path_buf(),
+            io: BufReader::new(stream),
+        };
+        // Greeting looks like `{"QMP": {...}}`, then capabilities must be
+        // negotiated before any other command is accepted.
+        qmp.read_line().await;
+        qmp.exec(json!({"execute": "qmp_capabilities"}))
+            .await
+            .unwrap_or_else(|e| exits!(exitcode::OSERR, "Qmp: qmp_capabilities failed:{}", e));
+        qmp
+    }
+
+    async fn read_line(&mut self) -> Value {
+        let mut line = String::new();
+        match timeout(QMP_TIMEOUT, self.io.read_line(&mut line)).await {
+            Err(_) => exits!(exitcode::OSERR, "Qmp: timeout reading from {:?}", self.sock),
+            Ok(Ok(0)) => exits!(exitcode::OSERR, "Qmp: connection to {:?} closed", self.sock),
+            Ok(Ok(_)) => serde_json::from_str(&line).unwrap_or_else(|e| {
+                exits!(exitcode::SOFTWARE, "Qmp: bad reply `{}`:{}", line.trim(), e)
+            }),
+            Ok(Err(e)) => exits!(exitcode::OSERR, "Qmp: fail to read from {:?}:{}", self.sock, e),
+        }
+    }
+
+    /// Send one command and wait for its matching `"return"` reply,
+    /// skipping any asynchronous events received in between. A `"error"`
+    /// reply (e.g. an unknown or malformed command) ends the loop too, as
+    /// `Err` with qemu's own
+    /// description, instead of being mistaken for an event and stalling
+    /// until `QMP_TIMEOUT`.
+    async fn exec(&mut self, cmd: Value) -> Result<Value, String> {
+        let mut line = cmd.to_string();
+        line.push('\n');
+        self.io
+            .get_mut()
+            .write_all(line.as_bytes())
+            .await
+            .unwrap_or_else(|e| {
+                exits!(exitcode::OSERR, "Qmp: fail to write to {:?}:{}", self.sock, e)
+            });
+        loop {
+            let reply = self.read_line().await;
+            if let Some(err) = reply.get("error") {
+                let desc = err["desc"].as_str().unwrap_or("unknown error").to_string();
+                return Err(desc);
+            }
+            if reply.get("return").is_some() {
+                return Ok(reply);
+            }
+        }
+    }
+
+    /// Pass a human monitor command through QMP, e.g. `savevm`/`loadvm`.
+    /// HMP reports failures as an `"Error: ..."` return string rather than
+    /// a QMP `"error"` reply, so that text is turned into `Err` too.
+    async fn hmp(&mut self, cmd_line: &str) -> Result<Value, String> {
+        let reply = self
+            .exec(json!({
+                "execute": "human-monitor-command",
+                "arguments": {"command-line": cmd_line},
+            }))
+            .await?;
+        if let Some(out) = reply["return"].as_str() {
+            if out.trim_start().starts_with("Error") {
+                return Err(out.trim().to_string());
+            }
+        }
+        Ok(reply)
+    }
+
+    pub async fn query_status(&mut self) -> VirtualMachineState {
+        let reply = self
+            .exec(json!({"execute": "query-status"}))
+            .await
+            .unwrap_or_else(|e| exits!(exitcode::SOFTWARE, "Qmp: query-status failed:{}", e));
+        match reply["return"]["status"].as_str() {
+            Some("running") => VirtualMachineState::Running,
+            Some("paused") | Some("suspended") => VirtualMachineState::Paused,
+            _ => VirtualMachineState::Stopped,
+        }
+    }
+
+    /// Pause the VM, e.g. to inspect it during crash triage.
+    pub async fn stop(&mut self) {
+        self.exec(json!({"execute": "stop"}))
+            .await
+            .unwrap_or_else(|e| exits!(exitcode::SOFTWARE, "Qmp: stop failed:{}", e));
+    }
+
+    /// Resume a paused VM.
+    pub async fn cont(&mut self) {
+        self.exec(json!({"execute": "cont"}))
+            .await
+            .unwrap_or_else(|e| exits!(exitcode::SOFTWARE, "Qmp: cont failed:{}", e));
+    }
+
+    /// Snapshot the current state under `healer_base` so later executions
+    /// can reset to it instead of rebooting. Fails (without killing the
+    /// process) when the image doesn't support internal snapshots, e.g. a
+    /// raw image booted with `-snapshot`; the caller decides whether that's
+    /// fatal.
+    pub async fn save_snapshot(&mut self) -> Result<(), String> {
+        self.hmp(&format!("savevm {}", SNAPSHOT_TAG)).await?;
+        Ok(())
+    }
+
+    /// Restore the `healer_base` snapshot and resume execution.
+    pub async fn load_snapshot(&mut self) -> Result<(), String> {
+        self.hmp(&format!("loadvm {}", SNAPSHOT_TAG)).await?;
+        self.cont().await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    /// A `Qmp` wired to one end of a socket pair, with the other end handed
+    /// back so tests can script replies without a real qemu.
+    async fn connected_pair() -> (Qmp, UnixStream) {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let qmp = Qmp {
+            sock: PathBuf::from("test"),
+            io: BufReader::new(ours),
+        };
+        (qmp, theirs)
+    }
+
+    #[tokio::test]
+    async fn exec_skips_events_before_the_matching_return() {
+        let (mut qmp, mut peer) = connected_pair().await;
+        tokio::spawn(async move {
+            let mut sent = [0u8; 4096];
+            peer.read(&mut sent).await.unwrap();
+            peer.write_all(b"{\"event\": \"SHUTDOWN\"}\n").await.unwrap();
+            peer.write_all(b"{\"return\": {}}\n").await.unwrap();
+        });
+        let reply = qmp
+            .exec(json!({"execute": "query-status"}))
+            .await
+            .unwrap();
+        assert!(reply.get("return").is_some());
+    }
+
+    #[tokio::test]
+    async fn exec_returns_err_on_an_error_reply_instead_of_stalling() {
+        let (mut qmp, mut peer) = connected_pair().await;
+        tokio::spawn(async move {
+            let mut sent = [0u8; 4096];
+            peer.read(&mut sent).await.unwrap();
+            peer.write_all(b"{\"error\": {\"desc\": \"boom\"}}\n")
+                .await
+                .unwrap();
+        });
+        let err = qmp.exec(json!({"execute": "savevm"})).await.unwrap_err();
+        assert_eq!(err, "boom");
+    }
+}